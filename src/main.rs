@@ -10,6 +10,7 @@ extern crate hyper;
 #[macro_use]
 extern crate log;
 extern crate regex;
+extern crate crypto;
 
 extern crate yaml_rust;
 use std::str::FromStr;
@@ -21,16 +22,28 @@ use prettytable::row::Row;
 use prettytable::cell::Cell;
 
 use encoding::{Encoding, DecoderTrap};
-use argparse::{ArgumentParser, Store, List};
+use argparse::{ArgumentParser, Store, StoreTrue, List};
 use std::string::String;
 use std::vec::Vec;
-use std::collections::{HashMap, BTreeMap};
+use std::collections::{HashMap, BTreeMap, VecDeque};
 use std::io::Read;
 use std::ops::Deref;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant};
+use std::thread;
+use std::cmp;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 
 use hyper::client::response::Response;
+use hyper::net::{NetworkConnector, HttpStream};
 
 use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::io;
 
 use hyper::header::{Headers, AcceptCharset, Charset, qitem};
 use log::{LogRecord, LogLevel, LogMetadata, SetLoggerError, LogLevelFilter};
@@ -54,18 +67,36 @@ trait FromYaml where Self: Sized {
 }
 
 trait ToYaml<T> {
-    fn from_yaml(target: &Self) -> Yaml;
+    fn to_yaml(target: &Self) -> T;
 }
 
 struct Options {
     log_level: String,
     targets: Vec<String>,
+    no_cache: bool,
+    cache_dir: String,
+    concurrency: u32,
+    timeout_ms: u64,
+    max_retries: u32,
+    from: String,
+    to: String,
+    timeframe: String,
+    format: String,
+    output: String,
 }
 
 const SLEEP_BETWEEN_REQUESTS: u64 = 3000;
 const ICHARTS_URI: &'static str = "http://www.finam.ru/cache/icharts/icharts.js";
 const MARKETS_BASE_URI: &'static str = "http://www.finam.ru/profile/";
+const EXPORT_BASE_URI: &'static str = "http://export.finam.ru/";
 const LOGGER: &'static str = "finance-logger";
+const DEFAULT_CACHE_DIR: &'static str = ".finance-miner-cache";
+const DEFAULT_CONCURRENCY: u32 = 4;
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const RETRY_BASE_BACKOFF_MS: u64 = 500;
+const DEFAULT_TIMEFRAME: &'static str = "daily";
+const DEFAULT_FORMAT: &'static str = "table";
 
 
 #[derive(Debug)]
@@ -184,9 +215,41 @@ fn ensure_http_success(response: Response) -> Result<Response> {
 }
 
 
-fn download_finam_doc(uri: &str) -> Result<String> {
+/// Bounds the connect phase itself, unlike `set_read_timeout`/`set_write_timeout`.
+struct TimeoutConnector {
+    connect_timeout: Duration,
+}
+
+impl NetworkConnector for TimeoutConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<HttpStream> {
+        if scheme != "http" {
+            return Err(hyper::Error::Io(io::Error::new(io::ErrorKind::Other, format!("unsupported scheme: {}", scheme))));
+        }
+
+        let addrs = try!((host, port).to_socket_addrs());
+        let mut last_err = None;
+
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => return Ok(HttpStream(stream)),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(From::from(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, format!("could not resolve: {}", host)))))
+    }
+}
+
+fn download_finam_doc(uri: &str, timeout: Duration) -> Result<String> {
     info!("{}: Downloading financial data from: {}", LOGGER, uri);
-    hyper::Client::new()
+
+    let mut client = hyper::Client::with_connector(TimeoutConnector { connect_timeout: timeout });
+    client.set_read_timeout(Some(timeout));
+    client.set_write_timeout(Some(timeout));
+
+    client
         .get(uri)
         .header(AcceptCharset(vec![qitem(Charset::Ext("utf-8".to_string()))]))
         .send()
@@ -203,6 +266,108 @@ fn download_finam_doc(uri: &str) -> Result<String> {
         })
 }
 
+/// Gates the aggregate request rate across every worker thread.
+struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> RateLimiter {
+        RateLimiter { interval: interval, next_slot: Mutex::new(Instant::now()) }
+    }
+
+    fn acquire(&self) {
+        let wait = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let now = Instant::now();
+            let scheduled = if *next_slot > now { *next_slot } else { now };
+            *next_slot = scheduled + self.interval;
+            scheduled.duration_since(now)
+        };
+
+        if wait > Duration::from_millis(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Exponential backoff: `RETRY_BASE_BACKOFF_MS * 2^n`.
+fn with_retry<F, T>(max_retries: u32, mut attempt: F) -> Result<T>
+    where F: FnMut() -> Result<T>
+{
+    let mut last_err = None;
+
+    for retry in 0..(max_retries + 1) {
+        match attempt() {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                warn!("{}: Attempt {} of {} failed: {}", LOGGER, retry + 1, max_retries + 1, err);
+                last_err = Some(err);
+
+                if retry < max_retries {
+                    let backoff = RETRY_BASE_BACKOFF_MS * (1u64 << retry);
+                    thread::sleep(Duration::from_millis(backoff));
+                }
+            }
+        }
+    }
+
+    Err(last_err.unwrap())
+}
+
+/// `changed` is false when the body hashes the same as what was already cached.
+struct CachedDoc {
+    content: String,
+    changed: bool,
+}
+
+fn digest_of(content: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(content);
+    hasher.result_str()
+}
+
+fn cache_paths(cache_dir: &str, uri: &str) -> (PathBuf, PathBuf) {
+    let key = digest_of(uri);
+    (Path::new(cache_dir).join(format!("{}.doc", key)), Path::new(cache_dir).join(format!("{}.sha256", key)))
+}
+
+fn download_finam_doc_cached(uri: &str, cache_dir: &str, no_cache: bool, timeout: Duration) -> Result<CachedDoc> {
+    let content = try!(download_finam_doc(uri, timeout));
+
+    if no_cache {
+        return Ok(CachedDoc { content: content, changed: true });
+    }
+
+    try!(fs::create_dir_all(cache_dir));
+    let (doc_path, hash_path) = cache_paths(cache_dir, uri);
+    let hash = digest_of(&content);
+
+    let mut previous_hash = String::new();
+    let previously_cached = fs::File::open(&hash_path)
+        .and_then(|mut f| f.read_to_string(&mut previous_hash))
+        .is_ok();
+
+    let changed = !previously_cached || previous_hash != hash;
+
+    if changed {
+        if previously_cached {
+            warn!("{}: Cached content changed, replacing: {}", LOGGER, uri);
+        }
+
+        let mut doc_file = try!(fs::File::create(&doc_path));
+        try!(doc_file.write_all(content.as_bytes()));
+
+        let mut hash_file = try!(fs::File::create(&hash_path));
+        try!(hash_file.write_all(hash.as_bytes()));
+    } else {
+        info!("{}: Unchanged since last run, skipping: {}", LOGGER, uri);
+    }
+
+    Ok(CachedDoc { content: content, changed: changed })
+}
+
 fn extract_yaml_from_doc(regex: &str, body: &str) -> Vec<Yaml> {
     let re = regex::Regex::new(regex).unwrap();
 
@@ -220,6 +385,126 @@ fn extract_yaml_from_doc(regex: &str, body: &str) -> Vec<Yaml> {
     yamls_parsed
 }
 
+#[derive(Clone, Copy, Debug)]
+enum FieldKind {
+    Integer,
+    Str,
+}
+
+impl FieldKind {
+    fn describe(&self) -> &'static str {
+        match *self {
+            FieldKind::Integer => "integer",
+            FieldKind::Str => "string",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct FieldSpec {
+    path: &'static [&'static str],
+    kind: FieldKind,
+    required: bool,
+}
+
+/// In the style of imag's `FileHeaderSpec`.
+struct YamlSpec {
+    fields: &'static [FieldSpec],
+}
+
+enum FieldValue {
+    Integer(i64),
+    Str(String),
+}
+
+fn yaml_kind_name(yaml: &Yaml) -> &'static str {
+    match *yaml {
+        Yaml::Real(_) => "Real",
+        Yaml::Integer(_) => "Integer",
+        Yaml::String(_) => "String",
+        Yaml::Boolean(_) => "Boolean",
+        Yaml::Array(_) => "Array",
+        Yaml::Hash(_) => "Hash",
+        Yaml::Alias(_) => "Alias",
+        Yaml::Null => "Null",
+        Yaml::BadValue => "BadValue",
+    }
+}
+
+fn resolve_path<'a>(root: &'a Yaml, path: &[&str]) -> Option<&'a Yaml> {
+    let mut current = Some(root);
+
+    for key in path {
+        current = current.and_then(|yaml| yaml.as_hash())
+            .and_then(|hash| hash.get(&Yaml::from_str(key)));
+    }
+
+    current
+}
+
+/// Accumulates every missing/mismatched field instead of failing on the first one.
+fn validate(root: &Yaml, spec: &YamlSpec) -> Result<HashMap<String, FieldValue>> {
+    let mut values = HashMap::new();
+    let mut errors = Vec::<String>::new();
+
+    for field in spec.fields {
+        let path_str = field.path.join(".");
+
+        match resolve_path(root, field.path) {
+            None => {
+                if field.required {
+                    errors.push(format!("{}: required field not found", path_str));
+                }
+            }
+            Some(yaml) => match field.kind {
+                FieldKind::Integer => match yaml.as_i64() {
+                    Some(value) => { values.insert(path_str, FieldValue::Integer(value)); },
+                    None => errors.push(format!("{}: expected {}, found {}", path_str, field.kind.describe(), yaml_kind_name(yaml))),
+                },
+                FieldKind::Str => match yaml.as_str() {
+                    Some(value) => { values.insert(path_str, FieldValue::Str(value.to_string())); },
+                    None => errors.push(format!("{}: expected {}, found {}", path_str, field.kind.describe(), yaml_kind_name(yaml))),
+                },
+            },
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(values)
+    } else {
+        Err(MinerError::YamlConversionError(errors.join("; ")))
+    }
+}
+
+fn validated_str<'a>(values: &'a HashMap<String, FieldValue>, path: &str) -> Option<&'a str> {
+    match values.get(path) {
+        Some(&FieldValue::Str(ref value)) => Some(value.as_str()),
+        _ => None,
+    }
+}
+
+fn validated_i64(values: &HashMap<String, FieldValue>, path: &str) -> Option<i64> {
+    match values.get(path) {
+        Some(&FieldValue::Integer(value)) => Some(value),
+        _ => None,
+    }
+}
+
+const EMITENT_HEADER_SPEC: YamlSpec = YamlSpec { fields: &[
+    FieldSpec { path: &["header", "internal_id"], kind: FieldKind::Integer, required: true },
+    FieldSpec { path: &["header", "id"], kind: FieldKind::Str, required: true },
+    FieldSpec { path: &["header", "market_id"], kind: FieldKind::Str, required: false },
+    FieldSpec { path: &["header", "market_name"], kind: FieldKind::Str, required: false },
+    FieldSpec { path: &["header", "uri"], kind: FieldKind::Str, required: false },
+    FieldSpec { path: &["header", "code"], kind: FieldKind::Str, required: false },
+    FieldSpec { path: &["name"], kind: FieldKind::Str, required: false },
+]};
+
+const EMITENT_PROFILE_SPEC: YamlSpec = YamlSpec { fields: &[
+    FieldSpec { path: &["quote", "code"], kind: FieldKind::Str, required: false },
+    FieldSpec { path: &["quote", "market", "title"], kind: FieldKind::Str, required: false },
+]};
+
 #[derive(Default)]
 #[derive(Debug)]
 struct Emitent {
@@ -235,26 +520,385 @@ struct Emitent {
 
 impl FromYaml for Emitent {
     fn from_yaml(yaml: &Yaml) -> Result<Emitent> {
-        let mut result = Emitent::default();
-        let hash = match yaml.as_hash() {
-            Some(hash) => hash,
-            None => return Err(MinerError::YamlConversionError("Root value is not hash".to_string())),
-        };
+        let values = try!(validate(yaml, &EMITENT_HEADER_SPEC));
+
+        Ok(Emitent {
+            internal_id: validated_i64(&values, "header.internal_id").unwrap_or(0) as u64,
+            id: validated_str(&values, "header.id").unwrap_or("").to_string(),
+            market_id: validated_str(&values, "header.market_id").unwrap_or("").to_string(),
+            market_name: validated_str(&values, "header.market_name").unwrap_or("").to_string(),
+            uri: validated_str(&values, "header.uri").unwrap_or("").to_string(),
+            code: validated_str(&values, "header.code").unwrap_or("").to_string(),
+            name: validated_str(&values, "name").unwrap_or("").to_string(),
+        })
+    }
+}
+
+impl ToYaml<Yaml> for Emitent {
+    fn to_yaml(target: &Emitent) -> Yaml {
+        let mut header = BTreeMap::new();
+        header.insert(Yaml::from_str("internal_id"), Yaml::Integer(target.internal_id as i64));
+        header.insert(Yaml::from_str("id"), Yaml::String(target.id.clone()));
+        header.insert(Yaml::from_str("market_id"), Yaml::String(target.market_id.clone()));
+        header.insert(Yaml::from_str("market_name"), Yaml::String(target.market_name.clone()));
+        header.insert(Yaml::from_str("uri"), Yaml::String(target.uri.clone()));
+        header.insert(Yaml::from_str("code"), Yaml::String(target.code.clone()));
+
+        let mut root = BTreeMap::new();
+        root.insert(Yaml::from_str("header"), Yaml::Hash(header));
+        root.insert(Yaml::from_str("name"), Yaml::String(target.name.clone()));
+        // Body is reserved for per-instrument data (e.g. historical candles);
+        // `document_to_yaml` fills it in whenever the file is rewritten.
+        root.insert(Yaml::from_str("body"), Yaml::Hash(BTreeMap::new()));
+
+        Yaml::Hash(root)
+    }
+}
+
+fn emitent_file_name(emitent: &Emitent) -> String {
+    format!("{}.yaml", emitent.id)
+}
+
+fn emitent_file_path(target_dir: &str, emitent: &Emitent) -> PathBuf {
+    Path::new(target_dir).join(emitent_file_name(emitent))
+}
+
+fn load_yaml_document(path: &Path) -> Result<Yaml> {
+    let mut contents = String::new();
+    try!(try!(fs::File::open(path)).read_to_string(&mut contents));
+    let yamls = try!(YamlLoader::load_from_str(&contents));
+    yamls.first().cloned().ok_or(MinerError::YamlConversionError("Document is empty".to_string()))
+}
+
+fn load_emitent(path: &Path) -> Result<Emitent> {
+    let yaml = try!(load_yaml_document(path));
+    Emitent::from_yaml(&yaml)
+}
+
+/// Missing or unreadable files are treated as "nothing stored yet".
+fn load_candles(path: &Path) -> Vec<Candle> {
+    load_yaml_document(path)
+        .ok()
+        .and_then(|doc| doc.as_hash()
+            .and_then(|hash| hash.get(&Yaml::from_str("body")))
+            .and_then(|body| body.as_hash())
+            .and_then(|body| body.get(&Yaml::from_str("candles")))
+            .and_then(|candles| candles.as_vec())
+            .map(|candles| candles.iter().filter_map(|candle| Candle::from_yaml(candle).ok()).collect()))
+        .unwrap_or_else(Vec::new)
+}
+
+fn document_to_yaml(emitent: &Emitent, candles: &[Candle]) -> Yaml {
+    let mut doc = match ToYaml::to_yaml(emitent) {
+        Yaml::Hash(hash) => hash,
+        _ => unreachable!(),
+    };
+
+    let mut body = BTreeMap::new();
+    body.insert(Yaml::from_str("candles"), Yaml::Array(candles.iter().map(ToYaml::to_yaml).collect()));
+    doc.insert(Yaml::from_str("body"), Yaml::Hash(body));
+
+    Yaml::Hash(doc)
+}
+
+fn write_emitent_document(path: &Path, emitent: &Emitent, candles: &[Candle]) -> Result<()> {
+    let yaml = document_to_yaml(emitent, candles);
+
+    let mut rendered = String::new();
+    try!(YamlEmitter::new(&mut rendered).dump(&yaml));
+
+    let mut file = try!(fs::File::create(path));
+    try!(file.write_all(rendered.as_bytes()));
+
+    info!("{}: Stored emitent data at: {}", LOGGER, path.display());
+    Ok(())
+}
+
+/// Merges with whatever is already on disk so untouched fields and candles survive.
+fn store_emitent(target_dir: &str, emitent: &Emitent) -> Result<()> {
+    let path = emitent_file_path(target_dir, emitent);
+
+    let merged = match load_emitent(&path) {
+        Ok(existing) => Emitent {
+            internal_id: emitent.internal_id,
+            id: emitent.id.clone(),
+            name: if emitent.name.is_empty() { existing.name } else { emitent.name.clone() },
+            market_id: if emitent.market_id.is_empty() { existing.market_id } else { emitent.market_id.clone() },
+            market_name: if emitent.market_name.is_empty() { existing.market_name } else { emitent.market_name.clone() },
+            uri: if emitent.uri.is_empty() { existing.uri } else { emitent.uri.clone() },
+            code: if emitent.code.is_empty() { existing.code } else { emitent.code.clone() },
+        },
+        Err(_) => Emitent {
+            internal_id: emitent.internal_id,
+            id: emitent.id.clone(),
+            name: emitent.name.clone(),
+            market_id: emitent.market_id.clone(),
+            market_name: emitent.market_name.clone(),
+            uri: emitent.uri.clone(),
+            code: emitent.code.clone(),
+        },
+    };
+
+    let candles = load_candles(&path);
+    write_emitent_document(&path, &merged, &candles)
+}
+
+/// De-duplicates by datetime.
+fn merge_candles(existing: Vec<Candle>, fresh: Vec<Candle>) -> Vec<Candle> {
+    let mut by_datetime = BTreeMap::new();
+
+    for candle in existing.into_iter().chain(fresh.into_iter()) {
+        by_datetime.insert(candle.datetime.clone(), candle);
+    }
+
+    by_datetime.into_iter().map(|(_, candle)| candle).collect()
+}
+
+fn store_candles(target_dir: &str, emitent: &Emitent, fresh: Vec<Candle>) -> Result<()> {
+    let path = emitent_file_path(target_dir, emitent);
+    let existing = load_candles(&path);
+    let merged = merge_candles(existing, fresh);
+    write_emitent_document(&path, emitent, &merged)
+}
+
+#[derive(Debug, Clone)]
+struct Candle {
+    datetime: String,
+    open: f64,
+    high: f64,
+    low: f64,
+    close: f64,
+    volume: u64,
+}
+
+const CANDLE_SPEC: YamlSpec = YamlSpec { fields: &[
+    FieldSpec { path: &["datetime"], kind: FieldKind::Str, required: true },
+    FieldSpec { path: &["open"], kind: FieldKind::Str, required: true },
+    FieldSpec { path: &["high"], kind: FieldKind::Str, required: true },
+    FieldSpec { path: &["low"], kind: FieldKind::Str, required: true },
+    FieldSpec { path: &["close"], kind: FieldKind::Str, required: true },
+    FieldSpec { path: &["volume"], kind: FieldKind::Integer, required: true },
+]};
+
+impl FromYaml for Candle {
+    fn from_yaml(yaml: &Yaml) -> Result<Candle> {
+        let values = try!(validate(yaml, &CANDLE_SPEC));
+
+        fn parse_f64(values: &HashMap<String, FieldValue>, path: &str) -> Result<f64> {
+            validated_str(values, path)
+                .and_then(|value| value.parse::<f64>().ok())
+                .ok_or(MinerError::YamlConversionError(format!("{}: not a valid number", path)))
+        }
+
+        Ok(Candle {
+            datetime: validated_str(&values, "datetime").unwrap_or("").to_string(),
+            open: try!(parse_f64(&values, "open")),
+            high: try!(parse_f64(&values, "high")),
+            low: try!(parse_f64(&values, "low")),
+            close: try!(parse_f64(&values, "close")),
+            volume: validated_i64(&values, "volume").unwrap_or(0) as u64,
+        })
+    }
+}
+
+impl ToYaml<Yaml> for Candle {
+    fn to_yaml(target: &Candle) -> Yaml {
+        let mut hash = BTreeMap::new();
+        hash.insert(Yaml::from_str("datetime"), Yaml::String(target.datetime.clone()));
+        hash.insert(Yaml::from_str("open"), Yaml::String(target.open.to_string()));
+        hash.insert(Yaml::from_str("high"), Yaml::String(target.high.to_string()));
+        hash.insert(Yaml::from_str("low"), Yaml::String(target.low.to_string()));
+        hash.insert(Yaml::from_str("close"), Yaml::String(target.close.to_string()));
+        hash.insert(Yaml::from_str("volume"), Yaml::Integer(target.volume as i64));
+        Yaml::Hash(hash)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Date {
+    year: i32,
+    month: u32,
+    day: u32,
+}
+
+impl Date {
+    fn from_ymd(year: i32, month: u32, day: u32) -> Result<Date> {
+        if month < 1 || month > 12 {
+            return Err(MinerError::YamlConversionError(format!("Invalid month (expected 1-12): {}", month)));
+        }
+
+        let date = Date { year: year, month: month, day: day };
+
+        if day < 1 || day > date.days_in_month() {
+            return Err(MinerError::YamlConversionError(format!("Invalid day (expected 1-{}): {}", date.days_in_month(), day)));
+        }
+
+        Ok(date)
+    }
+
+    fn parse(value: &str) -> Result<Date> {
+        let parts: Vec<&str> = value.split('-').collect();
+
+        if parts.len() != 3 {
+            return Err(MinerError::YamlConversionError(format!("Invalid date (expected yyyy-mm-dd): {}", value)));
+        }
 
-        result.internal_id = try!(
-            hash.get(&Yaml::from_str("internal_id")).ok_or(MinerError::YamlConversionError("internal_id not found".to_string()))
-                .and_then(|yaml| yaml.as_i64().ok_or(MinerError::YamlConversionError("internal_id is not int".to_string())))
-                .and_then(|internal_id| Ok(internal_id as u64)));
+        let year = try!(parts[0].parse::<i32>().map_err(|_| MinerError::YamlConversionError(format!("Invalid year in date: {}", value))));
+        let month = try!(parts[1].parse::<u32>().map_err(|_| MinerError::YamlConversionError(format!("Invalid month in date: {}", value))));
+        let day = try!(parts[2].parse::<u32>().map_err(|_| MinerError::YamlConversionError(format!("Invalid day in date: {}", value))));
 
-        result.id = try!(
-            hash.get(&Yaml::from_str("id")).ok_or(MinerError::YamlConversionError("id not found".to_string()))
-                .and_then(|yaml| yaml.as_str().ok_or(MinerError::YamlConversionError("id is not string".to_string())))
-                .and_then(|id| Ok(id.to_string())));
+        Date::from_ymd(year, month, day)
+    }
+
+    /// Parses finam's raw `dtf=1` export date field (`YYYYMMDD`, no separators).
+    fn parse_finam(value: &str) -> Result<Date> {
+        if value.len() != 8 {
+            return Err(MinerError::YamlConversionError(format!("Invalid finam date (expected yyyymmdd): {}", value)));
+        }
+
+        let year = try!(value[0..4].parse::<i32>().map_err(|_| MinerError::YamlConversionError(format!("Invalid year in date: {}", value))));
+        let month = try!(value[4..6].parse::<u32>().map_err(|_| MinerError::YamlConversionError(format!("Invalid month in date: {}", value))));
+        let day = try!(value[6..8].parse::<u32>().map_err(|_| MinerError::YamlConversionError(format!("Invalid day in date: {}", value))));
+
+        Date::from_ymd(year, month, day)
+    }
+
+    fn is_leap_year(&self) -> bool {
+        (self.year % 4 == 0 && self.year % 100 != 0) || self.year % 400 == 0
+    }
+
+    fn days_in_month(&self) -> u32 {
+        match self.month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 => if self.is_leap_year() { 29 } else { 28 },
+            _ => 30,
+        }
+    }
 
-        Ok(result)
+    fn next_day(&self) -> Date {
+        if self.day < self.days_in_month() {
+            Date { year: self.year, month: self.month, day: self.day + 1 }
+        } else if self.month < 12 {
+            Date { year: self.year, month: self.month + 1, day: 1 }
+        } else {
+            Date { year: self.year + 1, month: 1, day: 1 }
+        }
     }
 }
 
+/// Granularity finam can export candles at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Timeframe {
+    Tick,
+    Minute1,
+    Daily,
+}
+
+impl Timeframe {
+    fn parse(value: &str) -> Result<Timeframe> {
+        match value {
+            "tick" => Ok(Timeframe::Tick),
+            "1min" => Ok(Timeframe::Minute1),
+            "daily" => Ok(Timeframe::Daily),
+            _ => Err(MinerError::YamlConversionError(format!("Unknown timeframe: {} (expected tick, 1min or daily)", value))),
+        }
+    }
+
+    fn period_code(&self) -> u32 {
+        match *self {
+            Timeframe::Tick => 1,
+            Timeframe::Minute1 => 2,
+            Timeframe::Daily => 8,
+        }
+    }
+}
+
+fn build_candles_uri(emitent: &Emitent, timeframe: Timeframe, from: Date, to: Date) -> String {
+    let filename = if !emitent.code.is_empty() { emitent.code.clone() } else { emitent.id.clone() };
+
+    format!(
+        "{base}{filename}.csv?market=0&em={market_id}&code={code}&apply=0&df={from_day}&mf={from_month}&yf={from_year}\
+         &from={from_day:02}.{from_month_1:02}.{from_year}&dt={to_day}&mt={to_month}&yt={to_year}&to={to_day:02}.{to_month_1:02}.{to_year}\
+         &p={period}&f={filename}&e=.csv&cn={code}&dtf=1&tmf=1&MSOR=0&mstime=on&mstimever=1&sep=1&sep2=1&datf=5&at=1",
+        base = EXPORT_BASE_URI,
+        filename = filename,
+        market_id = emitent.market_id,
+        code = filename,
+        from_day = from.day, from_month = from.month - 1, from_year = from.year, from_month_1 = from.month,
+        to_day = to.day, to_month = to.month - 1, to_year = to.year, to_month_1 = to.month,
+        period = timeframe.period_code())
+}
+
+/// finam's `datf=5` export: `<DATE>,<TIME>,<OPEN>,<HIGH>,<LOW>,<CLOSE>,<VOL>` rows, `<DATE>,...` header.
+fn parse_candles(csv: &str) -> Vec<Candle> {
+    csv.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('<'))
+        .filter_map(parse_candle_row)
+        .collect()
+}
+
+fn parse_candle_row(line: &str) -> Option<Candle> {
+    let fields: Vec<&str> = line.trim().split(',').collect();
+
+    if fields.len() < 7 {
+        return None;
+    }
+
+    let open = match fields[2].parse::<f64>() { Ok(value) => value, Err(_) => return None };
+    let high = match fields[3].parse::<f64>() { Ok(value) => value, Err(_) => return None };
+    let low = match fields[4].parse::<f64>() { Ok(value) => value, Err(_) => return None };
+    let close = match fields[5].parse::<f64>() { Ok(value) => value, Err(_) => return None };
+    let volume = match fields[6].parse::<u64>() { Ok(value) => value, Err(_) => return None };
+
+    Some(Candle {
+        datetime: format!("{} {}", fields[0], fields[1]),
+        open: open,
+        high: high,
+        low: low,
+        close: close,
+        volume: volume,
+    })
+}
+
+/// The sub-range of `[from, to]` after the last stored candle's date, or `None` once that date reaches `to`.
+/// Every date up to the last stored one counts as covered; gaps before it are not detected.
+fn narrow_range_to_missing(existing: &[Candle], from: Date, to: Date) -> Option<(Date, Date)> {
+    let last_stored = existing.iter()
+        .filter_map(|candle| candle.datetime.split(' ').next())
+        .filter_map(|date| Date::parse_finam(date).ok())
+        .max();
+
+    match last_stored {
+        Some(last) if last >= to => None,
+        Some(last) => Some((cmp::max(from, last.next_day()), to)),
+        None => Some((from, to)),
+    }
+}
+
+fn download_candles(emitent: &Emitent, target_dir: &str, cache_dir: &str, no_cache: bool, timeout: Duration, timeframe: Timeframe, from: Date, to: Date) -> Result<()> {
+    let path = emitent_file_path(target_dir, emitent);
+    let existing = load_candles(&path);
+
+    let (range_from, range_to) = match narrow_range_to_missing(&existing, from, to) {
+        Some(range) => range,
+        None => {
+            info!("{}: Candles already cover the requested range for: {}", LOGGER, emitent.name);
+            return Ok(());
+        }
+    };
+
+    let uri = build_candles_uri(emitent, timeframe, range_from, range_to);
+    let cached = try!(download_finam_doc_cached(&uri, cache_dir, no_cache, timeout));
+
+    if !cached.changed {
+        return Ok(());
+    }
+
+    let fresh = parse_candles(&cached.content);
+    store_candles(target_dir, emitent, fresh)
+}
+
 fn yaml_to_string(yaml: &Yaml) -> String {
     match yaml {
         &Yaml::Real(ref id) | &Yaml::String(ref id) => id.clone(),
@@ -268,25 +912,31 @@ fn yaml_to_string(yaml: &Yaml) -> String {
     }
 }
 
-fn download_emitent_info(uri: &str) -> Result<Yaml> {
-    let document = try!(download_finam_doc(uri));
+/// Returns `Ok(None)` when the cached page is unchanged, skipping the parse.
+fn download_emitent_info(uri: &str, cache_dir: &str, no_cache: bool, timeout: Duration) -> Result<Option<Yaml>> {
+    let cached = try!(download_finam_doc_cached(uri, cache_dir, no_cache, timeout));
+
+    if !cached.changed {
+        return Ok(None);
+    }
+
     let re = regex::Regex::new(r"Main.issue = (.*);").unwrap();
 
-    re.captures(&document).ok_or(ParseError::BlockNotFound("Emitent captures not found".to_string()))
+    re.captures(&cached.content).ok_or(ParseError::BlockNotFound("Emitent captures not found".to_string()))
         .and_then(|captures| captures.at(1).ok_or(ParseError::BlockNotFound("Emitent capture doesn't exist".to_string())))
         .and_then(|capture| YamlLoader::load_from_str(capture).map_err(|err| From::from(err)))
         .and_then(|yamls| {
             yamls.first().ok_or(ParseError::BlockNotFound("Yaml block cannot be decoded".to_string()))
 
         })
-        .map(|first| first.clone())
+        .map(|first| Some(first.clone()))
         .map_err(|err| From::from(err))
 }
 
-fn download_emitents_data() -> Vec<Emitent> {
-    let icharts = download_finam_doc(ICHARTS_URI)
-        .and_then(|charts| {
-            Ok(charts.replace("\r\n", "").replace("\n", "").replace(r"\'", "''"))
+fn download_emitents_data(cache_dir: &str, no_cache: bool, timeout: Duration, max_retries: u32) -> Vec<Emitent> {
+    let icharts = with_retry(max_retries, || download_finam_doc_cached(ICHARTS_URI, cache_dir, no_cache, timeout))
+        .and_then(|cached| {
+            Ok(cached.content.replace("\r\n", "").replace("\n", "").replace(r"\'", "''"))
         })
         .unwrap_or_else(|err| { println!("{}", err); std::process::exit(2); });
 
@@ -374,66 +1024,400 @@ fn download_emitents_data() -> Vec<Emitent> {
     result
 }
 
+#[derive(Clone, Copy)]
+struct CandleRange {
+    from: Date,
+    to: Date,
+    timeframe: Timeframe,
+}
+
+fn process_emitent(emitent: &mut Emitent, target_dir: &str, cache_dir: &str, no_cache: bool, timeout: Duration, max_retries: u32, limiter: &RateLimiter, candle_range: Option<CandleRange>) {
+    let uri = format!("{}/{}", MARKETS_BASE_URI, &emitent.uri);
+
+    match with_retry(max_retries, || {
+        limiter.acquire();
+        download_emitent_info(&uri, cache_dir, no_cache, timeout)
+    }) {
+        Ok(Some(yaml)) => {
+            let profile = match validate(&yaml, &EMITENT_PROFILE_SPEC) {
+                Ok(profile) => profile,
+                Err(err) => {
+                    println!("Cannot validate emitent profile for {}: {}", emitent.name, err);
+                    return;
+                }
+            };
+
+            emitent.code = validated_str(&profile, "quote.code")
+                .unwrap_or_else(|| {
+                    info!("{}: Code cannot be decoded for: {}", LOGGER, emitent.name);
+                    ""
+                })
+                .to_string();
+
+            emitent.market_name = validated_str(&profile, "quote.market.title")
+                .unwrap_or_else(|| {
+                    info!("{}: Market name cannot be decoded for: {}", LOGGER, emitent.name);
+                    ""
+                })
+                .to_string();
+        }
+        Ok(None) => {
+            info!("{}: Profile page unchanged, keeping previously stored data for: {}", LOGGER, emitent.name);
+
+            if let Ok(stored) = load_emitent(&emitent_file_path(target_dir, emitent)) {
+                emitent.code = stored.code;
+                emitent.market_name = stored.market_name;
+            }
+        }
+        Err(err) => {
+            println!("Cannot get emitent data for {}: {}", emitent.name, err);
+            return;
+        }
+    };
+
+    // Runs on its own schedule, whether or not the profile page above changed.
+    if let Some(range) = candle_range {
+        let result = with_retry(max_retries, || {
+            limiter.acquire();
+            download_candles(emitent, target_dir, cache_dir, no_cache, timeout, range.timeframe, range.from, range.to)
+        });
+
+        if let Err(err) = result {
+            println!("Cannot get candles for {}: {}", emitent.name, err);
+        }
+    }
+}
+
+fn download_emitents_concurrently(emitents: Vec<Emitent>, target_dir: &str, cache_dir: &str, no_cache: bool, concurrency: u32, timeout: Duration, max_retries: u32, candle_range: Option<CandleRange>) -> Vec<Emitent> {
+    let queue = Arc::new(Mutex::new(emitents.into_iter().collect::<VecDeque<Emitent>>()));
+    let limiter = Arc::new(RateLimiter::new(Duration::from_millis(SLEEP_BETWEEN_REQUESTS)));
+    let (tx, rx) = mpsc::channel();
+
+    let mut workers = Vec::new();
+
+    for _ in 0..concurrency {
+        let queue = queue.clone();
+        let limiter = limiter.clone();
+        let tx = tx.clone();
+        let target_dir = target_dir.to_string();
+        let cache_dir = cache_dir.to_string();
+
+        workers.push(thread::spawn(move || {
+            loop {
+                let mut emitent = {
+                    let mut queue = queue.lock().unwrap();
+                    match queue.pop_front() {
+                        Some(emitent) => emitent,
+                        None => break,
+                    }
+                };
+
+                process_emitent(&mut emitent, &target_dir, &cache_dir, no_cache, timeout, max_retries, &limiter, candle_range);
+
+                if let Err(err) = store_emitent(&target_dir, &emitent) {
+                    println!("Cannot store emitent data: {}", err);
+                }
+
+                tx.send(emitent).unwrap();
+            }
+        }));
+    }
+
+    drop(tx);
+
+    let mut results = Vec::new();
+    for emitent in rx.iter() {
+        results.push(emitent);
+    }
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    results
+}
+
+trait EmitentSink {
+    fn write(&mut self, emitent: &Emitent) -> Result<()>;
+    fn finalize(&mut self) -> Result<()>;
+}
+
+struct TableSink {
+    table: Table,
+    out: Box<Write>,
+}
+
+impl EmitentSink for TableSink {
+    fn write(&mut self, emitent: &Emitent) -> Result<()> {
+        self.table.add_row(Row::new(vec![
+            Cell::new(&emitent.id),
+            Cell::new(&emitent.code),
+            Cell::new(&emitent.name),
+            Cell::new(&emitent.market_name),
+        ]));
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        try!(self.table.print(&mut self.out));
+        Ok(())
+    }
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+struct CsvSink {
+    out: Box<Write>,
+    wrote_header: bool,
+}
+
+impl EmitentSink for CsvSink {
+    fn write(&mut self, emitent: &Emitent) -> Result<()> {
+        if !self.wrote_header {
+            try!(writeln!(self.out, "id,code,name,market_name"));
+            self.wrote_header = true;
+        }
+
+        try!(writeln!(self.out, "{},{},{},{}",
+            csv_escape(&emitent.id), csv_escape(&emitent.code), csv_escape(&emitent.name), csv_escape(&emitent.market_name)));
+
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn json_quote(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            _ => result.push(c),
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+fn yaml_to_json(yaml: &Yaml) -> String {
+    match *yaml {
+        Yaml::String(ref value) => json_quote(value),
+        Yaml::Integer(value) => value.to_string(),
+        Yaml::Real(ref value) => value.clone(),
+        Yaml::Boolean(value) => value.to_string(),
+        Yaml::Array(ref values) => format!("[{}]", values.iter().map(yaml_to_json).collect::<Vec<String>>().join(",")),
+        Yaml::Hash(ref hash) => {
+            let entries: Vec<String> = hash.iter()
+                .map(|(key, value)| format!("{}:{}", json_quote(&yaml_to_string(key)), yaml_to_json(value)))
+                .collect();
+            format!("{{{}}}", entries.join(","))
+        }
+        Yaml::Null | Yaml::BadValue | Yaml::Alias(_) => "null".to_string(),
+    }
+}
+
+struct JsonSink {
+    out: Box<Write>,
+    entries: Vec<Yaml>,
+}
+
+impl EmitentSink for JsonSink {
+    fn write(&mut self, emitent: &Emitent) -> Result<()> {
+        self.entries.push(ToYaml::to_yaml(emitent));
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        let json = yaml_to_json(&Yaml::Array(self.entries.clone()));
+        try!(writeln!(self.out, "{}", json));
+        Ok(())
+    }
+}
+
+fn open_sink_writer(target_dir: &str, output: &str) -> Result<Box<Write>> {
+    if output.is_empty() {
+        Ok(Box::new(std::io::stdout()))
+    } else {
+        let file = try!(fs::File::create(Path::new(target_dir).join(output)));
+        Ok(Box::new(file))
+    }
+}
+
+fn build_sink(format: &str, target_dir: &str, output: &str) -> Result<Box<EmitentSink>> {
+    let out = try!(open_sink_writer(target_dir, output));
+
+    match format {
+        "table" => Ok(Box::new(TableSink { table: Table::new(), out: out })),
+        "csv" => Ok(Box::new(CsvSink { out: out, wrote_header: false })),
+        "json" => Ok(Box::new(JsonSink { out: out, entries: Vec::new() })),
+        _ => Err(MinerError::YamlConversionError(format!("Unknown output format: {} (expected table, csv or json)", format))),
+    }
+}
+
 fn main() {
     log::set_logger(|max_log_level| {
         max_log_level.set(LogLevelFilter::Debug);
         Box::new(SimpleLogger)
     }).unwrap();
 
-    // let mut options = Options {
-    //     log_level: "INFO".to_string(),
-    //     targets: vec!()
-    // };
-    // {
-    //     let mut parser = ArgumentParser::new();
-    //     parser.set_description("Stocks market financial data miner");
-    //     parser.refer(&mut options.targets)
-    //         .add_argument("target", List, "targets")
-    //         .required();
-    //     parser.parse_args_or_exit();
-    // }
-
-    let mut table = Table::new();
-
-    for emitent in download_emitents_data().iter_mut().take(3) {
-
-        let yaml = match download_emitent_info(&format!("{}/{}", MARKETS_BASE_URI, &emitent.uri)) {
-            Ok(yaml) => yaml,
-            Err(err) => {
-                println!("Cannot get emitent data: {}", err);
-                continue;
+    let mut options = Options {
+        log_level: "INFO".to_string(),
+        targets: vec!(),
+        no_cache: false,
+        cache_dir: DEFAULT_CACHE_DIR.to_string(),
+        concurrency: DEFAULT_CONCURRENCY,
+        timeout_ms: DEFAULT_TIMEOUT_MS,
+        max_retries: DEFAULT_MAX_RETRIES,
+        from: String::new(),
+        to: String::new(),
+        timeframe: DEFAULT_TIMEFRAME.to_string(),
+        format: DEFAULT_FORMAT.to_string(),
+        output: String::new(),
+    };
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description("Stocks market financial data miner");
+        parser.refer(&mut options.targets)
+            .add_argument("target", List, "targets")
+            .required();
+        parser.refer(&mut options.no_cache)
+            .add_option(&["--no-cache"], StoreTrue, "Always refetch, ignoring the content-addressed cache");
+        parser.refer(&mut options.cache_dir)
+            .add_option(&["--cache-dir"], Store, "Directory to keep the content-addressed download cache in");
+        parser.refer(&mut options.concurrency)
+            .add_option(&["--concurrency"], Store, "Number of profile pages to download in parallel");
+        parser.refer(&mut options.timeout_ms)
+            .add_option(&["--timeout"], Store, "Per-request connect/read timeout, in milliseconds");
+        parser.refer(&mut options.max_retries)
+            .add_option(&["--max-retries"], Store, "Number of retries for a failed request before it is skipped");
+        parser.refer(&mut options.from)
+            .add_option(&["--from"], Store, "Start date (yyyy-mm-dd) to download historical candles from");
+        parser.refer(&mut options.to)
+            .add_option(&["--to"], Store, "End date (yyyy-mm-dd) to download historical candles to");
+        parser.refer(&mut options.timeframe)
+            .add_option(&["--timeframe"], Store, "Candle granularity: tick, 1min or daily");
+        parser.refer(&mut options.format)
+            .add_option(&["--format"], Store, "Summary output format: table, csv or json");
+        parser.refer(&mut options.output)
+            .add_option(&["--output"], Store, "File to write the summary to, inside the target directory (default: stdout)");
+        parser.parse_args_or_exit();
+    }
+
+    let target_dir = options.targets.first().cloned().unwrap_or_else(|| {
+        println!("No target directory given");
+        std::process::exit(2);
+    });
+
+    if let Err(err) = fs::create_dir_all(&target_dir) {
+        println!("Cannot create target directory: {}", err);
+        std::process::exit(2);
+    }
+
+    let candle_range = if options.from.is_empty() || options.to.is_empty() {
+        None
+    } else {
+        match (Date::parse(&options.from), Date::parse(&options.to), Timeframe::parse(&options.timeframe)) {
+            (Ok(from), Ok(to), Ok(timeframe)) => Some(CandleRange { from: from, to: to, timeframe: timeframe }),
+            (from, to, timeframe) => {
+                for err in vec![from.err(), to.err(), timeframe.err()].into_iter().filter_map(|err| err) {
+                    println!("{}", err);
+                }
+                std::process::exit(2);
             }
+        }
+    };
+
+    let timeout = Duration::from_millis(options.timeout_ms);
+    let emitents: Vec<Emitent> = download_emitents_data(&options.cache_dir, options.no_cache, timeout, options.max_retries)
+        .into_iter()
+        .take(3)
+        .collect();
+
+    let emitents = download_emitents_concurrently(emitents, &target_dir, &options.cache_dir, options.no_cache, options.concurrency, timeout, options.max_retries, candle_range);
+
+    let mut sink = match build_sink(&options.format, &target_dir, &options.output) {
+        Ok(sink) => sink,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
+    for emitent in &emitents {
+        if let Err(err) = sink.write(emitent) {
+            println!("Cannot write {} to output: {}", emitent.name, err);
+        }
+    }
+
+    if let Err(err) = sink.finalize() {
+        println!("Cannot finalize output: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candle_at(datetime: &str) -> Candle {
+        Candle { datetime: datetime.to_string(), open: 0.0, high: 0.0, low: 0.0, close: 0.0, volume: 0 }
+    }
+
+    #[test]
+    fn emitent_round_trips_through_yaml() {
+        let emitent = Emitent {
+            internal_id: 42,
+            id: "issuer-id".to_string(),
+            name: "Some Issuer".to_string(),
+            market_id: "1".to_string(),
+            market_name: "MICEX".to_string(),
+            uri: "/issuer-id/".to_string(),
+            code: "ISSR".to_string(),
         };
 
-        emitent.code = yaml.as_hash()
-            .and_then(|hash| hash.get(&Yaml::from_str("quote")))
-            .and_then(|yaml| yaml.as_hash())
-            .and_then(|hash| hash.get(&Yaml::from_str("code")))
-            .and_then(|yaml| yaml.as_str())
-            .unwrap_or_else(|| {
-                info!("{}: Code cannot be decoded for: {}", LOGGER, emitent.name);
-                ""
-            })
-            .to_string();
-
-        emitent.market_name = yaml.as_hash()
-            .and_then(|hash| hash.get(&Yaml::from_str("quote")))
-            .and_then(|yaml| yaml.as_hash())
-            .and_then(|hash| hash.get(&Yaml::from_str("market")))
-            .and_then(|yaml| yaml.as_hash())
-            .and_then(|hash| hash.get(&Yaml::from_str("title")))
-            .and_then(|yaml| yaml.as_str())
-            .unwrap_or_else(|| {
-                info!("{}: Market name cannot be decoded for: {}", LOGGER, emitent.name);
-                ""
-            })
-            .to_string();
-
-        println!("{:?}", emitent);
-        std::thread::sleep(std::time::Duration::new(SLEEP_BETWEEN_REQUESTS, 0));
-    }
-
-    // table.printstd();
-
-    // return;
+        let yaml = ToYaml::to_yaml(&emitent);
+        let round_tripped = Emitent::from_yaml(&yaml).unwrap();
+
+        assert_eq!(round_tripped.internal_id, emitent.internal_id);
+        assert_eq!(round_tripped.id, emitent.id);
+        assert_eq!(round_tripped.name, emitent.name);
+        assert_eq!(round_tripped.market_id, emitent.market_id);
+        assert_eq!(round_tripped.market_name, emitent.market_name);
+        assert_eq!(round_tripped.uri, emitent.uri);
+        assert_eq!(round_tripped.code, emitent.code);
+    }
+
+    #[test]
+    fn narrow_range_to_missing_skips_dates_already_stored() {
+        let from = Date::parse("2024-01-01").unwrap();
+        let to = Date::parse("2024-01-10").unwrap();
+        let existing = vec![candle_at("20240103 093000"), candle_at("20240105 093000")];
+
+        let narrowed = narrow_range_to_missing(&existing, from, to);
+
+        assert_eq!(narrowed, Some((Date::parse("2024-01-06").unwrap(), to)));
+    }
+
+    #[test]
+    fn narrow_range_to_missing_returns_none_once_fully_covered() {
+        let from = Date::parse("2024-01-01").unwrap();
+        let to = Date::parse("2024-01-05").unwrap();
+        let existing = vec![candle_at("20240105 093000")];
+
+        assert_eq!(narrow_range_to_missing(&existing, from, to), None);
+    }
 }